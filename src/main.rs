@@ -1,36 +1,257 @@
-/// arithmetic parser
-/// not check wrong grammar, assert grammar is right
+//! arithmetic parser
+//! malformed input is reported as an [`EvalError`] instead of a panic
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Errors produced while tokenizing or evaluating an arithmetic expression.
+///
+/// Every variant carries the byte offset into the original input where the
+/// problem was detected, so callers can point the user at the exact spot
+/// (see [`EvalError::render`]).
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    UnexpectedCharacter { byte: u8, pos: usize },
+    MismatchedParen { pos: usize },
+    MissingOperand { pos: usize },
+    MissingOperator { pos: usize },
+    DivisionByZero { pos: usize },
+    UnexpectedEnd { pos: usize },
+    TypeMismatch { pos: usize },
+    UndefinedVariable { name: String, pos: usize },
+    UnknownFunction { name: String, pos: usize },
+    WrongArgCount { name: String, expected: usize, got: usize, pos: usize },
+}
+
+impl EvalError {
+    /// Byte offset into the original input this error points at.
+    pub fn pos(&self) -> usize {
+        match self {
+            EvalError::UnexpectedCharacter { pos, .. } => *pos,
+            EvalError::MismatchedParen { pos } => *pos,
+            EvalError::MissingOperand { pos } => *pos,
+            EvalError::MissingOperator { pos } => *pos,
+            EvalError::DivisionByZero { pos } => *pos,
+            EvalError::UnexpectedEnd { pos } => *pos,
+            EvalError::TypeMismatch { pos } => *pos,
+            EvalError::UndefinedVariable { pos, .. } => *pos,
+            EvalError::UnknownFunction { pos, .. } => *pos,
+            EvalError::WrongArgCount { pos, .. } => *pos,
+        }
+    }
+
+    /// Renders the error message followed by the offending input with a
+    /// caret underlining the exact column, e.g.:
+    ///
+    /// ```text
+    /// division by zero at position 2
+    /// 1 / 0
+    ///   ^
+    /// ```
+    pub fn render(&self, input: &str) -> String {
+        let caret_line = " ".repeat(self.pos()) + "^";
+        format!("{}\n{}\n{}", self, input, caret_line)
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::UnexpectedCharacter { byte, pos } => {
+                write!(f, "unexpected character '{}' at position {}", *byte as char, pos)
+            }
+            EvalError::MismatchedParen { pos } => write!(f, "mismatched parenthesis at position {}", pos),
+            EvalError::MissingOperand { pos } => write!(f, "missing operand at position {}", pos),
+            EvalError::MissingOperator { pos } => write!(f, "missing operator at position {}", pos),
+            EvalError::DivisionByZero { pos } => write!(f, "division by zero at position {}", pos),
+            EvalError::UnexpectedEnd { pos } => write!(f, "unexpected end of input at position {}", pos),
+            EvalError::TypeMismatch { pos } => write!(f, "type mismatch at position {}", pos),
+            EvalError::UndefinedVariable { name, pos } => {
+                write!(f, "undefined variable '{}' at position {}", name, pos)
+            }
+            EvalError::UnknownFunction { name, pos } => {
+                write!(f, "unknown function '{}' at position {}", name, pos)
+            }
+            EvalError::WrongArgCount { name, expected, got, pos } => {
+                write!(f, "'{}' expects {} argument(s) but got {} at position {}", name, expected, got, pos)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// The result of evaluating a [`Value`] or [`Expression`]: either a number
+/// or a boolean, so that comparisons and boolean operators can live in the
+/// same tree as arithmetic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Computed {
+    Num(f64),
+    Bool(bool),
+}
+
+impl Computed {
+    fn as_num(self, pos: usize) -> Result<f64, EvalError> {
+        match self {
+            Computed::Num(n) => Ok(n),
+            Computed::Bool(_) => Err(EvalError::TypeMismatch { pos }),
+        }
+    }
+
+    fn as_bool(self, pos: usize) -> Result<bool, EvalError> {
+        match self {
+            Computed::Bool(b) => Ok(b),
+            Computed::Num(_) => Err(EvalError::TypeMismatch { pos }),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Value {
-    Literal(String),
+    Literal(String, usize),
+    Variable(String, usize),
+    Call { name: String, args: Vec<Value>, pos: usize },
     Expression(Box<Expression>)
 }
 
 #[derive(Debug)]
 pub enum Expression {
-    Add(Value, Value),
-    Sub(Value, Value),
-    Mul(Value, Value),
-    Div(Value, Value),
+    Add(Value, Value, usize),
+    Sub(Value, Value, usize),
+    Mul(Value, Value, usize),
+    Div(Value, Value, usize),
+    Mod(Value, Value, usize),
+    FloorDiv(Value, Value, usize),
+    Pow(Value, Value, usize),
+    Eq(Value, Value, usize),
+    Ne(Value, Value, usize),
+    Lt(Value, Value, usize),
+    Le(Value, Value, usize),
+    Gt(Value, Value, usize),
+    Ge(Value, Value, usize),
+    And(Value, Value, usize),
+    Or(Value, Value, usize),
 }
 
 impl Value {
-    pub fn value(&self) -> f32 {
+    /// Byte offset into the original input this value is reported at: where a
+    /// literal, variable or call starts, or where an expression's operator sits.
+    fn pos(&self) -> usize {
+        match self {
+            Value::Literal(_, pos) => *pos,
+            Value::Variable(_, pos) => *pos,
+            Value::Call { pos, .. } => *pos,
+            Value::Expression(ex) => ex.pos(),
+        }
+    }
+
+    pub fn value(&self, vars: &HashMap<String, f64>) -> Result<Computed, EvalError> {
         match self {
-            Value::Literal(num) => num.parse().unwrap(),
-            Value::Expression(ex) => ex.value()
+            Value::Literal(num, pos) => num.parse().map(Computed::Num).map_err(|_| EvalError::UnexpectedEnd { pos: *pos }),
+            Value::Variable(name, pos) => vars.get(name).copied().map(Computed::Num)
+                .ok_or_else(|| EvalError::UndefinedVariable { name: name.clone(), pos: *pos }),
+            Value::Call { name, args, pos } => {
+                let args = args.iter()
+                    .map(|arg| arg.value(vars)?.as_num(*pos))
+                    .collect::<Result<Vec<f64>, EvalError>>()?;
+                call_function(name, &args, *pos).map(Computed::Num)
+            }
+            Value::Expression(ex) => ex.value(vars)
         }
     }
 }
 
+/// Built-in functions callable as `name(arg, ...)`.
+fn call_function(name: &str, args: &[f64], pos: usize) -> Result<f64, EvalError> {
+    fn unary(name: &str, args: &[f64], pos: usize) -> Result<f64, EvalError> {
+        match args {
+            [a] => Ok(*a),
+            _ => Err(EvalError::WrongArgCount { name: name.to_string(), expected: 1, got: args.len(), pos }),
+        }
+    }
+
+    fn binary(name: &str, args: &[f64], pos: usize) -> Result<(f64, f64), EvalError> {
+        match args {
+            [a, b] => Ok((*a, *b)),
+            _ => Err(EvalError::WrongArgCount { name: name.to_string(), expected: 2, got: args.len(), pos }),
+        }
+    }
+
+    match name {
+        "sqrt" => Ok(unary(name, args, pos)?.sqrt()),
+        "abs" => Ok(unary(name, args, pos)?.abs()),
+        "floor" => Ok(unary(name, args, pos)?.floor()),
+        "ceil" => Ok(unary(name, args, pos)?.ceil()),
+        "sin" => Ok(unary(name, args, pos)?.sin()),
+        "cos" => Ok(unary(name, args, pos)?.cos()),
+        "tan" => Ok(unary(name, args, pos)?.tan()),
+        "min" => binary(name, args, pos).map(|(a, b)| a.min(b)),
+        "max" => binary(name, args, pos).map(|(a, b)| a.max(b)),
+        "pow" => binary(name, args, pos).map(|(a, b)| a.powf(b)),
+        _ => Err(EvalError::UnknownFunction { name: name.to_string(), pos }),
+    }
+}
+
 impl Expression {
-    pub fn value(&self) -> f32 {
+    /// Byte offset into the original input this expression's operator sits at.
+    fn pos(&self) -> usize {
         match self {
-            Expression::Add(l, r) => l.value() + r.value(),
-            Expression::Sub(l, r) => l.value() - r.value(),
-            Expression::Mul(l, r) => l.value() * r.value(),
-            Expression::Div(l, r) => l.value() / r.value(),
+            Expression::Add(_, _, pos) => *pos,
+            Expression::Sub(_, _, pos) => *pos,
+            Expression::Mul(_, _, pos) => *pos,
+            Expression::Div(_, _, pos) => *pos,
+            Expression::Mod(_, _, pos) => *pos,
+            Expression::FloorDiv(_, _, pos) => *pos,
+            Expression::Pow(_, _, pos) => *pos,
+            Expression::Eq(_, _, pos) => *pos,
+            Expression::Ne(_, _, pos) => *pos,
+            Expression::Lt(_, _, pos) => *pos,
+            Expression::Le(_, _, pos) => *pos,
+            Expression::Gt(_, _, pos) => *pos,
+            Expression::Ge(_, _, pos) => *pos,
+            Expression::And(_, _, pos) => *pos,
+            Expression::Or(_, _, pos) => *pos,
+        }
+    }
+
+    pub fn value(&self, vars: &HashMap<String, f64>) -> Result<Computed, EvalError> {
+        match self {
+            Expression::Add(l, r, pos) => Ok(Computed::Num(l.value(vars)?.as_num(*pos)? + r.value(vars)?.as_num(*pos)?)),
+            Expression::Sub(l, r, pos) => Ok(Computed::Num(l.value(vars)?.as_num(*pos)? - r.value(vars)?.as_num(*pos)?)),
+            Expression::Mul(l, r, pos) => Ok(Computed::Num(l.value(vars)?.as_num(*pos)? * r.value(vars)?.as_num(*pos)?)),
+            Expression::Div(l, r, pos) => {
+                let (l, r) = (l.value(vars)?.as_num(*pos)?, r.value(vars)?.as_num(*pos)?);
+                if r == 0f64 {
+                    Err(EvalError::DivisionByZero { pos: *pos })
+                } else {
+                    Ok(Computed::Num(l / r))
+                }
+            }
+            Expression::Mod(l, r, pos) => {
+                let (l, r) = (l.value(vars)?.as_num(*pos)?, r.value(vars)?.as_num(*pos)?);
+                if r == 0f64 {
+                    Err(EvalError::DivisionByZero { pos: *pos })
+                } else {
+                    Ok(Computed::Num(l % r))
+                }
+            }
+            Expression::FloorDiv(l, r, pos) => {
+                let (l, r) = (l.value(vars)?.as_num(*pos)?, r.value(vars)?.as_num(*pos)?);
+                if r == 0f64 {
+                    Err(EvalError::DivisionByZero { pos: *pos })
+                } else {
+                    Ok(Computed::Num((l / r).floor()))
+                }
+            }
+            Expression::Pow(l, r, pos) => Ok(Computed::Num(l.value(vars)?.as_num(*pos)?.powf(r.value(vars)?.as_num(*pos)?))),
+            Expression::Eq(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_num(*pos)? == r.value(vars)?.as_num(*pos)?)),
+            Expression::Ne(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_num(*pos)? != r.value(vars)?.as_num(*pos)?)),
+            Expression::Lt(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_num(*pos)? < r.value(vars)?.as_num(*pos)?)),
+            Expression::Le(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_num(*pos)? <= r.value(vars)?.as_num(*pos)?)),
+            Expression::Gt(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_num(*pos)? > r.value(vars)?.as_num(*pos)?)),
+            Expression::Ge(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_num(*pos)? >= r.value(vars)?.as_num(*pos)?)),
+            Expression::And(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_bool(*pos)? && r.value(vars)?.as_bool(*pos)?)),
+            Expression::Or(l, r, pos) => Ok(Computed::Bool(l.value(vars)?.as_bool(*pos)? || r.value(vars)?.as_bool(*pos)?)),
         }
     }
 }
@@ -38,59 +259,158 @@ impl Expression {
 #[derive(Debug, Clone)]
 pub enum Token{
     Number(String),
+    Identifier(String),
     LeftParen,
     RightParen,
+    Comma,
     OpAdd,
     OpSub,
     OpMul,
     OpDiv,
+    OpMod,
+    OpFloorDiv,
+    OpPow,
+    OpEq,
+    OpNe,
+    OpLt,
+    OpLe,
+    OpGt,
+    OpGe,
+    OpAnd,
+    OpOr,
+}
+
+/// Binding power of an operator token: `(precedence, right_associative)`.
+/// Higher precedence binds tighter. Returns `None` for non-operator tokens.
+fn precedence(token: &Token) -> Option<(u8, bool)> {
+    match token {
+        Token::OpOr => Some((1, false)),
+        Token::OpAnd => Some((2, false)),
+        Token::OpEq | Token::OpNe | Token::OpLt | Token::OpLe | Token::OpGt | Token::OpGe => Some((3, false)),
+        Token::OpAdd | Token::OpSub => Some((4, false)),
+        Token::OpMul | Token::OpDiv | Token::OpMod | Token::OpFloorDiv => Some((5, false)),
+        Token::OpPow => Some((6, true)),
+        _ => None,
+    }
+}
+
+/// A [`Token`] together with the byte range `[start, end)` it occupies in
+/// the original input.
+#[derive(Debug, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub start: usize,
+    pub end: usize,
 }
 
-fn parse(input: &str) -> Vec<Token>{
-    let mut tokens: Vec<Token> = vec![];
+fn parse(input: &str) -> Result<Vec<SpannedToken>, EvalError> {
+    let mut tokens: Vec<SpannedToken> = vec![];
     let bytes = input.as_bytes();
     let mut pos = 0usize;
-    
+
     let mut last_token : Option<Token> = None;
     loop {
         if pos >= bytes.len() {
             break;
         }
 
+        let start = pos;
+
         let token = match bytes[pos] {
             b' ' => { pos += 1; continue;},
             b'(' => Token::LeftParen,
             b')' => Token::RightParen,
+            b',' => Token::Comma,
             b'+' => {
                 Token::OpAdd
             },
             b'-' => {
                 match last_token {
-                    Some(Token::Number(_)) => {
+                    Some(Token::Number(_)) | Some(Token::Identifier(_)) | Some(Token::RightParen) => {
                         Token::OpSub
                     },
                     _ => {
-                        pos += 1; 
+                        pos += 1;
                         Token::Number(String::from("-") + &parse_num(&bytes, &mut pos))
                     }
                 }
             },
             b'*' => Token::OpMul,
-            b'/' => Token::OpDiv,
+            b'/' => {
+                if bytes.get(pos + 1) == Some(&b'/') {
+                    pos += 1;
+                    Token::OpFloorDiv
+                } else {
+                    Token::OpDiv
+                }
+            },
+            b'%' => Token::OpMod,
+            b'^' => Token::OpPow,
+            b'=' => {
+                if bytes.get(pos + 1) == Some(&b'=') {
+                    pos += 1;
+                    Token::OpEq
+                } else {
+                    return Err(EvalError::UnexpectedCharacter { byte: b'=', pos });
+                }
+            },
+            b'!' => {
+                if bytes.get(pos + 1) == Some(&b'=') {
+                    pos += 1;
+                    Token::OpNe
+                } else {
+                    return Err(EvalError::UnexpectedCharacter { byte: b'!', pos });
+                }
+            },
+            b'<' => {
+                if bytes.get(pos + 1) == Some(&b'=') {
+                    pos += 1;
+                    Token::OpLe
+                } else {
+                    Token::OpLt
+                }
+            },
+            b'>' => {
+                if bytes.get(pos + 1) == Some(&b'=') {
+                    pos += 1;
+                    Token::OpGe
+                } else {
+                    Token::OpGt
+                }
+            },
+            b'&' => {
+                if bytes.get(pos + 1) == Some(&b'&') {
+                    pos += 1;
+                    Token::OpAnd
+                } else {
+                    return Err(EvalError::UnexpectedCharacter { byte: b'&', pos });
+                }
+            },
+            b'|' => {
+                if bytes.get(pos + 1) == Some(&b'|') {
+                    pos += 1;
+                    Token::OpOr
+                } else {
+                    return Err(EvalError::UnexpectedCharacter { byte: b'|', pos });
+                }
+            },
             b'0'..=b'9' => {
                 Token::Number(parse_num(&bytes, &mut pos))
             },
-            _ => panic!("unkown character {}", bytes[pos] as char)
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                Token::Identifier(parse_ident(&bytes, &mut pos))
+            },
+            byte => return Err(EvalError::UnexpectedCharacter { byte, pos })
         };
 
         last_token = Some(token.clone());
 
-        tokens.push(token);
+        tokens.push(SpannedToken { token, start, end: pos + 1 });
 
         pos += 1;
     }
 
-    tokens
+    Ok(tokens)
 }
 
 fn parse_num(bytes: &[u8], pos: &mut usize) -> String {
@@ -110,72 +430,185 @@ fn parse_num(bytes: &[u8], pos: &mut usize) -> String {
     num
 }
 
-fn reduce(stack: &mut Vec<Value>, opstack: &mut Vec<Token>) {
+fn parse_ident(bytes: &[u8], pos: &mut usize) -> String {
+    let mut ident = String::new();
+    loop {
+        if *pos >= bytes.len() {
+            break;
+        }
+        match bytes[*pos] {
+            n @ b'a'..=b'z' | n @ b'A'..=b'Z' | n @ b'0'..=b'9' | n @ b'_' => ident.push(n as char),
+            _ => break
+        };
+        *pos += 1;
+    }
+
+    *pos -= 1;
+    ident
+}
+
+fn apply_op(stack: &mut Vec<Value>, op: SpannedToken) -> Result<(), EvalError> {
+    let rv = stack.pop().ok_or(EvalError::MissingOperand { pos: op.start })?;
+    let lv = stack.pop().ok_or(EvalError::MissingOperand { pos: op.start })?;
+    let expr = match op.token {
+        Token::OpAdd => Expression::Add(lv, rv, op.start),
+        Token::OpSub => Expression::Sub(lv, rv, op.start),
+        Token::OpMul => Expression::Mul(lv, rv, op.start),
+        Token::OpDiv => Expression::Div(lv, rv, op.start),
+        Token::OpMod => Expression::Mod(lv, rv, op.start),
+        Token::OpFloorDiv => Expression::FloorDiv(lv, rv, op.start),
+        Token::OpPow => Expression::Pow(lv, rv, op.start),
+        Token::OpEq => Expression::Eq(lv, rv, op.start),
+        Token::OpNe => Expression::Ne(lv, rv, op.start),
+        Token::OpLt => Expression::Lt(lv, rv, op.start),
+        Token::OpLe => Expression::Le(lv, rv, op.start),
+        Token::OpGt => Expression::Gt(lv, rv, op.start),
+        Token::OpGe => Expression::Ge(lv, rv, op.start),
+        Token::OpAnd => Expression::And(lv, rv, op.start),
+        Token::OpOr => Expression::Or(lv, rv, op.start),
+        _ => unreachable!()
+    };
+    stack.push(Value::Expression(Box::new(expr)));
+
+    Ok(())
+}
+
+fn reduce(stack: &mut Vec<Value>, opstack: &mut Vec<SpannedToken>) -> Result<(), EvalError> {
     while let Some(op) = opstack.pop() {
-        let rv = stack.pop().unwrap();
-        let lv = stack.pop().unwrap();
-        match op {
-            Token::OpAdd => stack.push(Value::Expression(Box::new(Expression::Add(lv, rv)))),
-            Token::OpSub => stack.push(Value::Expression(Box::new(Expression::Sub(lv, rv)))),
-            Token::OpMul => stack.push(Value::Expression(Box::new(Expression::Mul(lv, rv)))),
-            Token::OpDiv => stack.push(Value::Expression(Box::new(Expression::Div(lv, rv)))),
-            _ => unreachable!()
+        apply_op(stack, op)?;
+    }
+
+    Ok(())
+}
+
+/// Pops and applies operators already on `opstack` that bind at least as
+/// tightly as `incoming_prec` (strictly tighter for the right-associative
+/// `^`), so the next operator is pushed only once the stack holds nothing
+/// it needs to yield to.
+fn reduce_tighter(stack: &mut Vec<Value>, opstack: &mut Vec<SpannedToken>, incoming_prec: u8, right_assoc: bool) -> Result<(), EvalError> {
+    loop {
+        let should_pop = match opstack.last() {
+            Some(top) => {
+                let (top_prec, _) = precedence(&top.token).expect("opstack only ever holds operators");
+                if right_assoc { top_prec > incoming_prec } else { top_prec >= incoming_prec }
+            }
+            None => false
+        };
+
+        if !should_pop {
+            break;
         }
+
+        let op = opstack.pop().unwrap();
+        apply_op(stack, op)?;
     }
+
+    Ok(())
 }
 
-pub fn eval_expression(tokens: &[Token], pos: &mut usize) -> Value {
+/// Parses the comma-separated argument list of a call, with `pos` pointing
+/// just past the opening `(`. Leaves `pos` on the closing `)`.
+fn parse_call_args(tokens: &[SpannedToken], pos: &mut usize) -> Result<Vec<Value>, EvalError> {
+    let mut args = Vec::new();
+
+    if matches!(tokens.get(*pos).map(|t| &t.token), Some(Token::RightParen)) {
+        return Ok(args);
+    }
+
+    loop {
+        args.push(eval_expression(tokens, pos)?);
+
+        match tokens.get(*pos).map(|t| &t.token) {
+            Some(Token::Comma) => { *pos += 1; }
+            _ => break,
+        }
+    }
+
+    Ok(args)
+}
+
+pub fn eval_expression(tokens: &[SpannedToken], pos: &mut usize) -> Result<Value, EvalError> {
     let mut stack : Vec<Value> = Vec::new();
-    let mut opstack: Vec<Token> = Vec::new();
-    
+    let mut opstack: Vec<SpannedToken> = Vec::new();
+
     loop {
         if *pos >= tokens.len() {
             break;
         }
-        match tokens[*pos] {
-            Token::Number(ref num) => stack.push(Value::Literal(num.clone())),
-            ref op @ Token::OpAdd | ref op @ Token::OpSub => {
-                reduce(&mut stack, &mut opstack);
+        let current = tokens[*pos].clone();
+        match current.token {
+            Token::Number(ref num) => stack.push(Value::Literal(num.clone(), current.start)),
+            Token::Identifier(ref name) => {
+                let is_call = tokens.get(*pos + 1)
+                    .map(|next| matches!(next.token, Token::LeftParen) && next.start == current.end)
+                    .unwrap_or(false);
 
-                opstack.push(op.clone());
-            },
-            ref op @ Token::OpMul | ref op @ Token::OpDiv  => {
-                match opstack.last() {
-                    Some(Token::OpMul) | Some(Token::OpDiv) => {
-                        reduce(&mut stack, &mut opstack);
+                if is_call {
+                    *pos += 2;
+                    let args = parse_call_args(tokens, pos)?;
+                    match tokens.get(*pos) {
+                        Some(SpannedToken { token: Token::RightParen, .. }) => {},
+                        _ => return Err(EvalError::MismatchedParen { pos: current.start })
                     }
-                    _ => ()
+                    stack.push(Value::Call { name: name.clone(), args, pos: current.start });
+                } else {
+                    stack.push(Value::Variable(name.clone(), current.start));
                 }
-                opstack.push(op.clone())
             },
             Token::LeftParen => {
                 *pos += 1;
-                stack.push(eval_expression(tokens, pos));
-                //println!("{:?}", stack);
+                let value = eval_expression(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(SpannedToken { token: Token::RightParen, .. }) => stack.push(value),
+                    _ => return Err(EvalError::MismatchedParen { pos: current.start })
+                }
             },
-            Token::RightParen => {
-                //*pos += 1;
+            Token::RightParen | Token::Comma => {
                 break;
+            },
+            ref op => {
+                let (prec, right_assoc) = precedence(op).expect("only operator tokens reach here");
+                reduce_tighter(&mut stack, &mut opstack, prec, right_assoc)?;
+                opstack.push(current.clone());
             }
         };
-        
+
         *pos += 1;
     }
 
-    reduce(&mut stack, &mut opstack);
+    reduce(&mut stack, &mut opstack)?;
+
+    let last_pos = tokens.get(*pos).map(|t| t.start).unwrap_or(0);
 
-    stack.pop().unwrap()
+    // More than one value left on the stack means two primaries were parsed
+    // back-to-back with no operator between them, e.g. "1 2" or "(1 2) + 3".
+    if let Some(extra) = stack.get(1) {
+        return Err(EvalError::MissingOperator { pos: extra.pos() });
+    }
+
+    stack.pop().ok_or(EvalError::MissingOperand { pos: last_pos })
+}
+
+pub fn eval_value(input: &str) -> Result<Computed, EvalError> {
+    eval_value_with(input, &HashMap::new())
 }
 
-pub fn eval_value(input: &str) -> f32 {
-    let tokens = parse(input);
+/// Evaluates `input`, resolving any [`Value::Variable`] references against
+/// `vars`. An identifier absent from `vars` surfaces as
+/// [`EvalError::UndefinedVariable`].
+pub fn eval_value_with(input: &str, vars: &HashMap<String, f64>) -> Result<Computed, EvalError> {
+    let tokens = parse(input)?;
     // println!("{:?}", tokens);
 
     let mut pos = 0usize;
-    let value = eval_expression(&tokens, &mut pos);
+    let value = eval_expression(&tokens, &mut pos)?;
+
+    if pos < tokens.len() {
+        return Err(EvalError::MismatchedParen { pos: tokens[pos].start });
+    }
 
     //println!("{:?}", value);
-    value.value()
+    value.value(vars)
 }
 
 fn main() {
@@ -184,69 +617,227 @@ fn main() {
 
 #[cfg(test)]
 mod test{
-    use super::eval_value;
+    use super::{eval_value, eval_value_with, Computed, EvalError};
+    use std::collections::HashMap;
+
+    fn num(n: f64) -> Result<Computed, EvalError> {
+        Ok(Computed::Num(n))
+    }
+
+    fn boolean(b: bool) -> Result<Computed, EvalError> {
+        Ok(Computed::Bool(b))
+    }
+
     #[test]
     fn test_number(){
-        assert_eq!(eval_value("3"), 3f32);
-        assert_eq!(eval_value("5"), 5f32);
-        assert_eq!(eval_value("-5"), -5f32);
+        assert_eq!(eval_value("3"), num(3.0));
+        assert_eq!(eval_value("5"), num(5.0));
+        assert_eq!(eval_value("-5"), num(-5.0));
     }
 
     #[test]
     fn test_add()
-    {        
-        assert_eq!(eval_value("3 + 4"), 7f32);
-        assert_eq!(eval_value("1 + 0"), 1f32);
-        assert_eq!(eval_value("-1 + 0"), -1f32);
-        assert_eq!(eval_value("1 + 3 + 4"), 8f32);
-        assert_eq!(eval_value("333 + 222"), 555f32);
+    {
+        assert_eq!(eval_value("3 + 4"), num(7.0));
+        assert_eq!(eval_value("1 + 0"), num(1.0));
+        assert_eq!(eval_value("-1 + 0"), num(-1.0));
+        assert_eq!(eval_value("1 + 3 + 4"), num(8.0));
+        assert_eq!(eval_value("333 + 222"), num(555.0));
     }
 
     #[test]
     fn test_sub()
-    {        
-        assert_eq!(eval_value("3 - 2"), 1f32);
-        assert_eq!(eval_value("13 - 21 - 12"), -20f32);
-        assert_eq!(eval_value("333 - 21"), 312f32);
+    {
+        assert_eq!(eval_value("3 - 2"), num(1.0));
+        assert_eq!(eval_value("13 - 21 - 12"), num(-20.0));
+        assert_eq!(eval_value("333 - 21"), num(312.0));
     }
 
     #[test]
     fn test_mul()
-    {        
-        assert_eq!(eval_value("3 * 5"), 15f32);
-        assert_eq!(eval_value("3 * 5 * 4"), 60f32);
+    {
+        assert_eq!(eval_value("3 * 5"), num(15.0));
+        assert_eq!(eval_value("3 * 5 * 4"), num(60.0));
     }
 
     #[test]
     fn test_div()
-    {        
-        assert_eq!(eval_value("3 / 2"), 1.5f32);
+    {
+        assert_eq!(eval_value("3 / 2"), num(1.5));
     }
 
     #[test]
     fn test_together()
-    {        
-        assert_eq!(eval_value("3 * 4 + 5 - 2"), 15f32);
+    {
+        assert_eq!(eval_value("3 * 4 + 5 - 2"), num(15.0));
     }
 
     #[test]
     fn test_paren()
-    {        
-        assert_eq!(eval_value("(1 + 2) * 4 / 2"), 6f32);
-        assert_eq!(eval_value("12 / 6 * 3 + 2 * (111 - 11)"), 206f32);
+    {
+        assert_eq!(eval_value("(1 + 2) * 4 / 2"), num(6.0));
+        assert_eq!(eval_value("12 / 6 * 3 + 2 * (111 - 11)"), num(206.0));
     }
 
     #[test]
     fn test_negative()
     {
-        assert_eq!(eval_value("(1 + -2) * 4 / 2"), -2f32);
-        assert_eq!(eval_value("(1 + -2) * 4 / -2"), 2f32);
+        assert_eq!(eval_value("(1 + -2) * 4 / 2"), num(-2.0));
+        assert_eq!(eval_value("(1 + -2) * 4 / -2"), num(2.0));
     }
 
     #[test]
     fn test_float()
     {
-        assert_eq!(eval_value("1 - 2.05"), -1.05f32);
-        assert_eq!(eval_value("0.86 * 2"), 1.72f32);
+        match eval_value("1 - 2.05").unwrap() {
+            Computed::Num(n) => assert!((n - -1.05).abs() < 1e-9, "{} != -1.05", n),
+            other => panic!("expected a number, got {:?}", other),
+        }
+        assert_eq!(eval_value("0.86 * 2"), num(1.72));
+    }
+
+    #[test]
+    fn test_pow()
+    {
+        assert_eq!(eval_value("2 ^ 3"), num(8.0));
+        assert_eq!(eval_value("2 ^ 3 ^ 2"), num(512.0));
+        assert_eq!(eval_value("2 * 2 ^ 3"), num(16.0));
+    }
+
+    #[test]
+    fn test_mod()
+    {
+        assert_eq!(eval_value("7 % 3"), num(1.0));
+        assert_eq!(eval_value("1 + 7 % 3"), num(2.0));
+    }
+
+    #[test]
+    fn test_floor_div()
+    {
+        assert_eq!(eval_value("7 // 2"), num(3.0));
+        assert_eq!(eval_value("-7 // 2"), num(-4.0));
+    }
+
+    #[test]
+    fn test_comparison()
+    {
+        assert_eq!(eval_value("1 < 2"), boolean(true));
+        assert_eq!(eval_value("2 <= 2"), boolean(true));
+        assert_eq!(eval_value("3 > 4"), boolean(false));
+        assert_eq!(eval_value("3 >= 3"), boolean(true));
+        assert_eq!(eval_value("3 == 3"), boolean(true));
+        assert_eq!(eval_value("3 != 3"), boolean(false));
+    }
+
+    #[test]
+    fn test_boolean_operators()
+    {
+        assert_eq!(eval_value("1 < 2 && 3 < 4"), boolean(true));
+        assert_eq!(eval_value("1 < 2 && 3 > 4"), boolean(false));
+        assert_eq!(eval_value("1 > 2 || 3 < 4"), boolean(true));
+        assert_eq!(eval_value("(1 + 2) * 4 >= 10 && 3 < 5"), boolean(true));
+    }
+
+    #[test]
+    fn test_type_mismatch()
+    {
+        assert_eq!(eval_value("1 && 2"), Err(EvalError::TypeMismatch { pos: 2 }));
+        assert_eq!(eval_value("(1 < 2) + 1"), Err(EvalError::TypeMismatch { pos: 8 }));
+    }
+
+    #[test]
+    fn test_variable()
+    {
+        let mut vars = HashMap::new();
+        vars.insert(String::from("price"), 10.0);
+        vars.insert(String::from("qty"), 3.0);
+        vars.insert(String::from("discount"), 5.0);
+
+        assert_eq!(eval_value_with("price * qty - discount", &vars), num(25.0));
+        assert_eq!(eval_value_with("price", &vars), num(10.0));
+    }
+
+    #[test]
+    fn test_undefined_variable()
+    {
+        assert_eq!(eval_value("price * qty"), Err(EvalError::UndefinedVariable { name: String::from("price"), pos: 0 }));
+    }
+
+    #[test]
+    fn test_function_call()
+    {
+        assert_eq!(eval_value("sqrt(16)"), num(4.0));
+        assert_eq!(eval_value("max(3, 7)"), num(7.0));
+        assert_eq!(eval_value("min(3, 7)"), num(3.0));
+        assert_eq!(eval_value("abs(-5)"), num(5.0));
+        assert_eq!(eval_value("1 + sqrt(9) * 2"), num(7.0));
+        assert_eq!(eval_value("pow(2, 10)"), num(1024.0));
+        assert_eq!(eval_value("max(min(1, 2), 3)"), num(3.0));
+    }
+
+    #[test]
+    fn test_unknown_function()
+    {
+        assert_eq!(eval_value("frobnicate(1)"), Err(EvalError::UnknownFunction { name: String::from("frobnicate"), pos: 0 }));
+    }
+
+    #[test]
+    fn test_wrong_arg_count()
+    {
+        assert_eq!(eval_value("sqrt(1, 2)"), Err(EvalError::WrongArgCount { name: String::from("sqrt"), expected: 1, got: 2, pos: 0 }));
+        assert_eq!(eval_value("max(1)"), Err(EvalError::WrongArgCount { name: String::from("max"), expected: 2, got: 1, pos: 0 }));
+    }
+
+    #[test]
+    fn test_call_args_missing_comma()
+    {
+        // A missing comma between arguments must surface as an error, not
+        // silently drop the first argument and mis-report the arg count.
+        assert_eq!(eval_value("max(3 7)"), Err(EvalError::MissingOperator { pos: 6 }));
+    }
+
+    #[test]
+    fn test_bare_identifier_before_paren_is_not_a_call()
+    {
+        // A space before "(" keeps this a plain variable reference followed
+        // by a parenthesized group, not a call - so it must not silently
+        // evaluate to the group's value.
+        assert_eq!(eval_value("sqrt (4)"), Err(EvalError::MissingOperator { pos: 6 }));
+    }
+
+    #[test]
+    fn test_division_by_zero()
+    {
+        assert_eq!(eval_value("1 / 0"), Err(EvalError::DivisionByZero { pos: 2 }));
+    }
+
+    #[test]
+    fn test_unexpected_character()
+    {
+        assert_eq!(eval_value("1 + @"), Err(EvalError::UnexpectedCharacter { byte: b'@', pos: 4 }));
+    }
+
+    #[test]
+    fn test_mismatched_paren()
+    {
+        assert_eq!(eval_value("(1 + 2"), Err(EvalError::MismatchedParen { pos: 0 }));
+        assert_eq!(eval_value("1 + 2)"), Err(EvalError::MismatchedParen { pos: 5 }));
+    }
+
+    #[test]
+    fn test_missing_operator()
+    {
+        assert_eq!(eval_value("1 2"), Err(EvalError::MissingOperator { pos: 2 }));
+        assert_eq!(eval_value("(1 2) + 3"), Err(EvalError::MissingOperator { pos: 3 }));
+
+        let vars = HashMap::new();
+        assert_eq!(eval_value_with("price qty", &vars), Err(EvalError::MissingOperator { pos: 6 }));
+    }
+
+    #[test]
+    fn test_render_points_at_position()
+    {
+        let err = eval_value("1 / 0").unwrap_err();
+        assert_eq!(err.render("1 / 0"), "division by zero at position 2\n1 / 0\n  ^");
     }
 }